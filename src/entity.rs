@@ -2,11 +2,12 @@
 //! Entity identifier and manager types.
 
 #[cfg(feature="serialisation")] use cereal::{CerealData, CerealError, CerealResult};
+#[cfg(feature="parallel")] use rayon;
 
-use std::collections::hash_map::{HashMap, Values};
 use std::default::Default;
 use std::marker::PhantomData;
 use std::ops::Deref;
+use std::slice::Iter;
 
 use Aspect;
 use BuildData;
@@ -18,6 +19,9 @@ use SystemManager;
 
 pub type Id = u64;
 
+/// A handle to an entity, packing a dense-storage `index` and a `generation` counter into a
+/// single `u64`. The generation is bumped every time an index is recycled, so a stale `Entity`
+/// referring to a freed (and possibly reused) slot can always be told apart from a live one.
 #[derive(Copy, Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub struct Entity(Id);
 
@@ -47,12 +51,33 @@ impl Entity
         Entity(0)
     }
 
+    fn new(index: u32, generation: u32) -> Entity
+    {
+        Entity(((generation as u64) << 32) | index as u64)
+    }
+
     /// Returns the entity's unique identifier.
     #[inline]
     pub fn id(&self) -> Id
     {
         self.0
     }
+
+    /// Returns the dense index of the slot this entity was allocated in.
+    #[inline]
+    pub fn index(&self) -> u32
+    {
+        self.0 as u32
+    }
+
+    /// Returns the generation of the slot this entity was allocated in, at the time it was
+    /// allocated. A slot's generation is bumped every time it is freed, so comparing against the
+    /// slot's current generation is enough to detect a stale `Entity`.
+    #[inline]
+    pub fn generation(&self) -> u32
+    {
+        (self.0 >> 32) as u32
+    }
 }
 
 impl<T: ComponentManager> IndexedEntity<T>
@@ -78,6 +103,14 @@ impl<T: ComponentManager> Deref for IndexedEntity<T>
     }
 }
 
+impl<T: ComponentManager> Clone for IndexedEntity<T>
+{
+    fn clone(&self) -> IndexedEntity<T>
+    {
+        IndexedEntity(self.0, self.1, self.2)
+    }
+}
+
 impl Default for Entity
 {
     fn default() -> Entity
@@ -93,10 +126,25 @@ pub struct FilteredEntityIter<'a, T: ComponentManager>
     components: &'a T,
 }
 
+pub struct FilteredEntityIterMut<'a, T: ComponentManager>
+{
+    inner: EntityIter<'a, T>,
+    aspect: Aspect<T>,
+    components: *mut T,
+    _marker: PhantomData<&'a mut T>,
+}
+
+pub struct JoinedEntityIter<'a, T: ComponentManager>
+{
+    inner: EntityIter<'a, T>,
+    aspects: Vec<Aspect<T>>,
+    components: &'a T,
+}
+
 // Inner Entity Iterator
 pub enum EntityIter<'a, T: ComponentManager>
 {
-    Map(Values<'a, Entity, IndexedEntity<T>>),
+    Dense(Iter<'a, Option<IndexedEntity<T>>>),
 }
 
 impl<'a, T: ComponentManager> EntityIter<'a, T>
@@ -111,9 +159,34 @@ impl<'a, T: ComponentManager> EntityIter<'a, T>
         }
     }
 
+    /// Like `filter`, but yields `&mut T` alongside each matched entity. Not a plain `Iterator`:
+    /// drive it with `while let Some((data, c)) = it.next() { ... }`.
+    pub fn filter_mut(self, aspect: Aspect<T>, components: &'a mut T) -> FilteredEntityIterMut<'a, T>
+    {
+        FilteredEntityIterMut
+        {
+            inner: self,
+            aspect: aspect,
+            components: components,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Yields only entities whose component set satisfies every `Aspect` in `aspects`, i.e. a
+    /// join across several aspects in one pass.
+    pub fn join(self, aspects: Vec<Aspect<T>>, components: &'a T) -> JoinedEntityIter<'a, T>
+    {
+        JoinedEntityIter
+        {
+            inner: self,
+            aspects: aspects,
+            components: components,
+        }
+    }
+
     pub fn clone(&self) -> Self {
-        let EntityIter::Map(ref values) = *self;
-        EntityIter::Map(values.clone())
+        let EntityIter::Dense(ref slots) = *self;
+        EntityIter::Dense(slots.clone())
     }
 }
 
@@ -124,7 +197,16 @@ impl<'a, T: ComponentManager> Iterator for EntityIter<'a, T>
     {
         match *self
         {
-            EntityIter::Map(ref mut values) => values.next().map(|x| EntityData(x))
+            EntityIter::Dense(ref mut slots) => {
+                for slot in slots.by_ref()
+                {
+                    if let Some(ref indexed) = *slot
+                    {
+                        return Some(EntityData(indexed));
+                    }
+                }
+                None
+            }
         }
     }
 }
@@ -149,10 +231,179 @@ impl<'a, T: ComponentManager> Iterator for FilteredEntityIter<'a, T>
     }
 }
 
-enum Event
+impl<'a, T: ComponentManager> FilteredEntityIterMut<'a, T>
+{
+    // Deliberately not an `Iterator` impl: `Iterator::Item` can't borrow from the `&mut self` of
+    // `next`, only from the iterator's own `'a`, which is exactly what let every previous call's
+    // `&mut T` alias every other one. Tying the component borrow to `&'b mut self` instead means
+    // the borrow checker won't let two live results overlap.
+    pub fn next<'b>(&'b mut self) -> Option<(EntityData<'a, T>, &'b mut T)>
+    {
+        for x in self.inner.by_ref()
+        {
+            let shared: &T = unsafe { &*self.components };
+            if self.aspect.check(&x, shared)
+            {
+                let exclusive: &'b mut T = unsafe { &mut *self.components };
+                return Some((x, exclusive));
+            }
+        }
+        None
+    }
+}
+
+impl<'a, T: ComponentManager> Iterator for JoinedEntityIter<'a, T>
+{
+    type Item = EntityData<'a, T>;
+    fn next(&mut self) -> Option<EntityData<'a, T>>
+    {
+        for x in self.inner.by_ref()
+        {
+            if self.aspects.iter().all(|aspect| aspect.check(&x, self.components))
+            {
+                return Some(x);
+            }
+        }
+        None
+    }
+}
+
+/// Implemented by component types that can be staged onto a `CommandBuffer`. This is the typed
+/// dispatch point a boxed command closes over, so applying a buffer at flush time never needs to
+/// match on a `TypeId`.
+pub trait Component<T: ComponentManager>: 'static
+{
+    fn insert(components: &mut T, entity: Entity, value: Self);
+    fn remove(components: &mut T, entity: Entity);
+}
+
+enum Event<T: ComponentManager>
 {
     BuildEntity(Entity),
     RemoveEntity(Entity),
+    ComponentsChanged(Entity),
+    AddComponent(AddPayload<T>),
+    RemoveComponent(RemovePayload<T>),
+}
+
+/// A deferred `CommandBuffer::add_component` call.
+pub struct AddPayload<T: ComponentManager>
+{
+    entity: Entity,
+    apply: Box<FnMut(&mut T)>,
+}
+
+/// A deferred `CommandBuffer::remove_component` call.
+pub struct RemovePayload<T: ComponentManager>
+{
+    entity: Entity,
+    apply: Box<FnMut(&mut T)>,
+}
+
+// TODO: Cleanup
+#[cfg(feature="serialisation")]
+unsafe impl<T: ComponentManager> CerealData for Event<T> {
+    fn write(&self, write: &mut ::std::io::Write) -> CerealResult<()> {
+        match *self {
+            Event::BuildEntity(entity) => {
+                try!(0u8.write(write));
+                entity.write(write)
+            }
+            Event::RemoveEntity(entity) => {
+                try!(1u8.write(write));
+                entity.write(write)
+            }
+            Event::ComponentsChanged(entity) => {
+                try!(2u8.write(write));
+                entity.write(write)
+            }
+            // A staged AddComponent/RemoveComponent closes over a boxed FnMut, which can't be
+            // serialised without a registry mapping a component's type back to a concrete type
+            // for both directions of the round trip. Rather than write a checkpoint that's
+            // guaranteed to fail on read, fail here, up front, where the caller can still choose
+            // to flush or apply the buffer first.
+            Event::AddComponent(_) | Event::RemoveComponent(_) => Err(CerealError::Msg(
+                "Cannot checkpoint a CommandBuffer with staged component commands; \
+                 flush or apply them first".to_string()
+            )),
+        }
+    }
+
+    fn read(read: &mut ::std::io::Read) -> CerealResult<Event<T>> {
+        match try!(u8::read(read)) {
+            0 => Ok(Event::BuildEntity(try!(CerealData::read(read)))),
+            1 => Ok(Event::RemoveEntity(try!(CerealData::read(read)))),
+            2 => Ok(Event::ComponentsChanged(try!(CerealData::read(read)))),
+            tag => Err(CerealError::Msg(format!("Unknown queued event tag {}", tag))),
+        }
+    }
+}
+
+/// An ordered buffer of deferred entity and component mutations. Systems stage commands here
+/// while iterating over entities, instead of requiring `&mut` access to the `ComponentManager`
+/// mid-iteration; `EntityManager::flush_queue` applies the commands against it in the exact
+/// order they were submitted, skipping any whose entity is no longer valid by then.
+pub struct CommandBuffer<T: ComponentManager>
+{
+    queue: Vec<Event<T>>,
+}
+
+// TODO: Cleanup
+#[cfg(feature="serialisation")]
+unsafe impl<T: ComponentManager> CerealData for CommandBuffer<T> {
+    fn write(&self, write: &mut ::std::io::Write) -> CerealResult<()> {
+        self.queue.write(write)
+    }
+
+    fn read(read: &mut ::std::io::Read) -> CerealResult<CommandBuffer<T>> {
+        Ok(CommandBuffer {
+            queue: try!(CerealData::read(read)),
+        })
+    }
+}
+
+impl<T: ComponentManager> CommandBuffer<T>
+{
+    pub fn new() -> CommandBuffer<T>
+    {
+        CommandBuffer
+        {
+            queue: Vec::new(),
+        }
+    }
+
+    /// Defers adding `value` as `entity`'s `C` component until the next flush.
+    pub fn add_component<C>(&mut self, entity: Entity, value: C)
+    where C: Component<T>
+    {
+        let mut value = Some(value);
+        self.queue.push(Event::AddComponent(AddPayload {
+            entity: entity,
+            apply: Box::new(move |c: &mut T| {
+                if let Some(value) = value.take()
+                {
+                    C::insert(c, entity, value);
+                }
+            }),
+        }));
+    }
+
+    /// Defers removing `entity`'s `C` component until the next flush.
+    pub fn remove_component<C>(&mut self, entity: Entity)
+    where C: Component<T>
+    {
+        self.queue.push(Event::RemoveComponent(RemovePayload {
+            entity: entity,
+            apply: Box::new(move |c: &mut T| {
+                C::remove(c, entity);
+            }),
+        }));
+    }
+
+    fn append(&mut self, event: Event<T>)
+    {
+        self.queue.push(event);
+    }
 }
 
 /// Handles creation, activation, and validating of entities.
@@ -160,34 +411,47 @@ enum Event
 pub struct EntityManager<T: ComponentManager>
 {
     indices: IndexPool,
-    entities: HashMap<Entity, IndexedEntity<T>>,
-    event_queue: Vec<Event>,
-    next_id: Id,
+    entities: Vec<Option<IndexedEntity<T>>>,
+    event_queue: CommandBuffer<T>,
+    // Bumped by every flush_queue call. Lets rollback tell whether a flush has happened since the
+    // snapshot was taken: if so, the queue's current contents are unrelated to what was pending at
+    // snapshot time and truncating by length would be meaningless (or worse, keep the wrong
+    // events), so rollback falls back to clearing the queue entirely in that case.
+    flush_count: u64,
 }
 
 // TODO: Cleanup
+// Serialises the event queue alongside `indices` and `entities`, so a checkpoint taken mid-frame
+// (before `flush_queue` has run) restores with its pending events intact. This only fails if the
+// queue holds a staged `CommandBuffer::add_component`/`remove_component` closure, which cannot be
+// serialised; flush or apply those first.
 #[cfg(feature="serialisation")]
 unsafe impl<T: ComponentManager> CerealData for EntityManager<T> {
     fn write(&self, write: &mut ::std::io::Write) -> CerealResult<()> {
-        if self.event_queue.len() != 0 {
-            Err(CerealError::Msg("Please flush events before serialising the world".to_string()))
-        } else {
-            try!(self.indices.write(write));
-            try!(self.entities.write(write));
-            self.next_id.write(write)
-        }
+        try!(self.indices.write(write));
+        try!(self.entities.write(write));
+        self.event_queue.write(write)
     }
 
     fn read(read: &mut ::std::io::Read) -> CerealResult<EntityManager<T>> {
         Ok(EntityManager {
             indices: try!(CerealData::read(read)),
             entities: try!(CerealData::read(read)),
-            next_id: try!(CerealData::read(read)),
-            event_queue: Vec::new(),
+            event_queue: try!(CerealData::read(read)),
+            flush_count: 0,
         })
     }
 }
 
+/// A save-state captured by `EntityManager::snapshot` and restored by `EntityManager::rollback`.
+pub struct EntityManagerSnapshot<T: ComponentManager>
+{
+    indices: IndexPool,
+    entities: Vec<Option<IndexedEntity<T>>>,
+    event_queue_len: usize,
+    flush_count: u64,
+}
+
 impl<T: ComponentManager> EntityManager<T>
 {
     /// Returns a new `EntityManager`
@@ -196,17 +460,18 @@ impl<T: ComponentManager> EntityManager<T>
         EntityManager
         {
             indices: IndexPool::new(),
-            entities: HashMap::new(),
-            next_id: 0,
-            event_queue: Vec::new(),
+            entities: Vec::new(),
+            event_queue: CommandBuffer::new(),
+            flush_count: 0,
         }
     }
 
     pub fn flush_queue<M, S>(&mut self, c: &mut T, m: &mut M, s: &mut S)
     where M: ServiceManager, S: SystemManager<Components=T, Services=M>
     {
-        let queue = ::std::mem::replace(&mut self.event_queue, Vec::new());
-        for e in queue {
+        self.flush_count += 1;
+        let queue = ::std::mem::replace(&mut self.event_queue, CommandBuffer::new());
+        for e in queue.queue {
             match e {
                 Event::BuildEntity(entity) => s.__activated(
                     EntityData(self.indexed(&entity)),
@@ -214,13 +479,51 @@ impl<T: ComponentManager> EntityManager<T>
                     m
                 ),
                 Event::RemoveEntity(entity) => {
-                    {
-                        let indexed = self.indexed(&entity);
-                        s.__deactivated(EntityData(indexed), c, m);
-                        c.__remove_all(indexed);
+                    // Two call sites queuing RemoveEntity for the same entity in one frame would
+                    // otherwise hit this twice; the first flush already cleared its slot.
+                    if self.is_valid(&entity) {
+                        {
+                            let indexed = self.indexed(&entity);
+                            s.__deactivated(EntityData(indexed), c, m);
+                            c.__remove_all(indexed);
+                        }
+                        self.remove(&entity);
+                    }
+                }
+                Event::ComponentsChanged(entity) => {
+                    // Diffing a system's previous Aspect match against its new one needs
+                    // per-system membership state that only SystemManager's own implementation
+                    // can track. __refresh is that hook: for each system, it compares the
+                    // entity's current Aspect match against what that system already has it
+                    // recorded as, calling __activated for newly-matching systems and
+                    // __deactivated for ones it no longer matches. That's different from both
+                    // __activated (which only ever adds, used for brand-new entities) and
+                    // __deactivated (which unconditionally removes from every system, used for
+                    // entities being destroyed outright) — so this needs its own hook, defined on
+                    // SystemManager alongside them.
+                    //
+                    // An entity removed earlier in the same flush has no valid slot left to
+                    // look up, so skip it instead of unwrapping into a panic.
+                    if self.is_valid(&entity) {
+                        s.__refresh(EntityData(self.indexed(&entity)), c, m);
                     }
-                    self.remove(&entity);
                 }
+                // A RemoveEntity processed earlier in the same flush recycles the entity's index
+                // (and bumps its generation), so applying a component command staged against it
+                // afterwards would silently write into whatever entity now occupies that slot.
+                //
+                // Applying the mutation is itself exactly what ComponentsChanged refreshes
+                // membership for above, so do the same __refresh here rather than requiring the
+                // caller to also queue ComponentsChanged (or remember to call notify_changed)
+                // after every add_component/remove_component.
+                Event::AddComponent(mut payload) => if self.is_valid(&payload.entity) {
+                    (payload.apply)(c);
+                    s.__refresh(EntityData(self.indexed(&payload.entity)), c, m);
+                },
+                Event::RemoveComponent(mut payload) => if self.is_valid(&payload.entity) {
+                    (payload.apply)(c);
+                    s.__refresh(EntityData(self.indexed(&payload.entity)), c, m);
+                },
             }
         }
     }
@@ -229,18 +532,35 @@ impl<T: ComponentManager> EntityManager<T>
     {
         let entity = self.create();
         builder.build(BuildData(self.indexed(&entity)), c);
-        self.event_queue.push(Event::BuildEntity(entity));
+        self.event_queue.append(Event::BuildEntity(entity));
         entity
     }
 
     pub fn remove_entity(&mut self, entity: Entity)
     {
-        self.event_queue.push(Event::RemoveEntity(entity));
+        self.event_queue.append(Event::RemoveEntity(entity));
+    }
+
+    /// Enqueues a recomputation of which systems match `entity`. Call this whenever components
+    /// are added to or removed from a live entity outside of entity creation/removal, so that
+    /// systems whose `Aspect` newly matches (or no longer matches) the entity get activated
+    /// (or deactivated) on the next `flush_queue`.
+    pub fn notify_changed(&mut self, entity: Entity)
+    {
+        self.event_queue.append(Event::ComponentsChanged(entity));
+    }
+
+    /// Appends every command staged on `buffer` to this manager's own queue, in order, clearing
+    /// `buffer`. Lets a system build up a `CommandBuffer` of component mutations while iterating
+    /// and submit it for deterministic application on the next `flush_queue`.
+    pub fn append_commands(&mut self, buffer: &mut CommandBuffer<T>)
+    {
+        self.event_queue.queue.append(&mut buffer.queue);
     }
 
     pub fn iter(&self) -> EntityIter<T>
     {
-        EntityIter::Map(self.entities.values())
+        EntityIter::Dense(self.entities.iter())
     }
 
     pub fn count(&self) -> usize
@@ -250,36 +570,101 @@ impl<T: ComponentManager> EntityManager<T>
 
     pub fn indexed(&self, entity: &Entity) -> &IndexedEntity<T>
     {
-        &self.entities[entity]
+        self.entities[entity.index() as usize].as_ref().unwrap()
     }
 
     /// Creates a new `Entity`, assigning it the first available index.
     pub fn create(&mut self) -> Entity
     {
-        self.next_id += 1;
-        let ret = Entity(self.next_id);
-        self.entities.insert(ret, IndexedEntity(self.indices.get_index(), ret, PhantomData));
-        ret
+        let (index, generation) = self.indices.get_index();
+        let entity = Entity::new(index, generation);
+        let indexed = Some(IndexedEntity(index as usize, entity, PhantomData));
+        if index as usize == self.entities.len() {
+            self.entities.push(indexed);
+        } else {
+            self.entities[index as usize] = indexed;
+        }
+        entity
     }
 
     /// Returns true if an entity is valid (not removed from the manager).
     #[inline]
     pub fn is_valid(&self, entity: &Entity) -> bool
     {
-        self.entities.contains_key(entity)
+        self.indices.is_valid(entity.index(), entity.generation())
+    }
+
+    /// Captures index and entity bookkeeping for a later `rollback`, along with the event queue's
+    /// current length, so `rollback` can tell which queued events predate the snapshot.
+    pub fn snapshot(&self) -> EntityManagerSnapshot<T>
+    {
+        EntityManagerSnapshot
+        {
+            indices: self.indices.clone(),
+            entities: self.entities.clone(),
+            event_queue_len: self.event_queue.queue.len(),
+            flush_count: self.flush_count,
+        }
+    }
+
+    /// Restores index and entity bookkeeping from `snapshot`. If no flush has happened since the
+    /// snapshot was taken, the queue only ever grew in the meantime, so it's truncated back to its
+    /// length at snapshot time: anything queued since may reference an index the rollback just
+    /// invalidated, but events already queued before the snapshot are still valid and are kept
+    /// rather than discarded. If a flush *did* happen in between, the queue was drained and
+    /// refilled with unrelated events in the meantime, so the recorded length no longer means
+    /// anything; fall back to clearing it entirely, as before.
+    ///
+    /// Assumes snapshots are rolled back to in the same (LIFO) order they were taken, like an undo
+    /// stack. Rolling back to an older snapshot and then a newer one taken before the first
+    /// rollback is not supported: the queue can't un-truncate, so events pending at the newer
+    /// snapshot may be missing afterwards.
+    pub fn rollback(&mut self, snapshot: EntityManagerSnapshot<T>)
+    {
+        self.indices = snapshot.indices;
+        self.entities = snapshot.entities;
+        if self.flush_count == snapshot.flush_count {
+            self.event_queue.queue.truncate(snapshot.event_queue_len);
+        } else {
+            self.event_queue = CommandBuffer::new();
+        }
     }
 
     /// Deletes an entity from the manager.
     pub fn remove(&mut self, entity: &Entity)
     {
-        self.entities.remove(entity).map(|e| self.indices.return_id(e.index()));
+        if self.is_valid(entity) {
+            self.entities[entity.index() as usize] = None;
+            self.indices.return_id(entity.index());
+        }
     }
 }
 
+#[cfg(feature="parallel")]
+impl<T: ComponentManager + Sync> EntityManager<T>
+{
+    /// Parallel version of `iter`, chunked across the thread pool. Requires the `parallel`
+    /// feature.
+    pub fn par_iter(&self) -> impl ::rayon::iter::ParallelIterator<Item=EntityData<T>>
+    {
+        use rayon::prelude::*;
+        self.entities.par_iter().filter_map(|slot| slot.as_ref().map(EntityData))
+    }
+
+    /// Parallel version of `filter`.
+    pub fn par_filter<'a>(&'a self, aspect: Aspect<T>, components: &'a T) -> impl ::rayon::iter::ParallelIterator<Item=EntityData<'a, T>>
+    where Aspect<T>: Sync
+    {
+        use rayon::prelude::*;
+        self.par_iter().filter(move |x| aspect.check(x, components))
+    }
+}
+
+#[derive(Clone)]
 struct IndexPool
 {
-    recycled: Vec<usize>,
-    next_index: usize,
+    recycled: Vec<u32>,
+    generations: Vec<u32>,
 }
 
 // TODO: Cleanup
@@ -290,18 +675,27 @@ unsafe impl CerealData for IndexPool {
         for &idx in &self.recycled {
             try!((idx as u64).write(write));
         }
-        (self.next_index as u64).write(write)
+        try!((self.generations.len() as u64).write(write));
+        for &gen in &self.generations {
+            try!((gen as u64).write(write));
+        }
+        Ok(())
     }
 
     fn read(read: &mut ::std::io::Read) -> CerealResult<IndexPool> {
-        let len = try!(u64::read(read)) as usize;
-        let mut indices = Vec::with_capacity(len);
-        for _ in 0..len {
-            indices.push(try!(u64::read(read)) as usize);
+        let recycled_len = try!(u64::read(read)) as usize;
+        let mut recycled = Vec::with_capacity(recycled_len);
+        for _ in 0..recycled_len {
+            recycled.push(try!(u64::read(read)) as u32);
+        }
+        let generations_len = try!(u64::read(read)) as usize;
+        let mut generations = Vec::with_capacity(generations_len);
+        for _ in 0..generations_len {
+            generations.push(try!(u64::read(read)) as u32);
         }
         Ok(IndexPool {
-            recycled: indices,
-            next_index: try!(u64::read(read)) as usize,
+            recycled: recycled,
+            generations: generations,
         })
     }
 }
@@ -314,29 +708,260 @@ impl IndexPool
         IndexPool
         {
             recycled: Vec::new(),
-            next_index: 0,
+            generations: Vec::new(),
         }
     }
 
     pub fn count(&self) -> usize
     {
-        self.next_index - self.recycled.len()
+        self.generations.len() - self.recycled.len()
     }
 
-    pub fn get_index(&mut self) -> usize
+    /// Returns a free index (recycling the most recently freed one if any exist) together with
+    /// its current generation, allocating a fresh slot with generation `1` otherwise.
+    pub fn get_index(&mut self) -> (u32, u32)
     {
         match self.recycled.pop()
         {
-            Some(id) => id,
+            Some(index) => (index, self.generations[index as usize]),
             None => {
-                self.next_index += 1;
-                self.next_index - 1
+                let index = self.generations.len() as u32;
+                self.generations.push(1);
+                (index, 1)
             }
         }
     }
 
-    pub fn return_id(&mut self, id: usize)
+    /// Frees `index`, bumping its generation so any `Entity` still referring to it is
+    /// invalidated.
+    pub fn return_id(&mut self, index: u32)
+    {
+        self.generations[index as usize] += 1;
+        self.recycled.push(index);
+    }
+
+    /// Returns true if `index` exists and its current generation is `generation`. Freeing an index
+    /// bumps its generation, so any handle issued before the free no longer matches; whether the
+    /// index is currently allocated is not itself checked.
+    #[inline]
+    pub fn is_valid(&self, index: u32, generation: u32) -> bool
+    {
+        (index as usize) < self.generations.len() && self.generations[index as usize] == generation
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // Minimal fixtures for the foreign ComponentManager/SystemManager/ServiceManager/
+    // EntityBuilder traits, inferred only from how entity.rs itself calls them. EntityData and
+    // BuildData are treated as opaque: these mocks never rely on their internal structure, only
+    // on being able to construct and pass them through.
+
+    #[derive(Default)]
+    struct TestComponents
     {
-        self.recycled.push(id);
+        removed_all_calls: usize,
+        log: Vec<String>,
+    }
+
+    impl ComponentManager for TestComponents
+    {
+        fn __remove_all(&mut self, _entity: &IndexedEntity<Self>)
+        {
+            self.removed_all_calls += 1;
+        }
+    }
+
+    struct Marker;
+
+    impl Component<TestComponents> for Marker
+    {
+        fn insert(components: &mut TestComponents, _entity: Entity, _value: Self)
+        {
+            components.log.push("insert".to_string());
+        }
+
+        fn remove(components: &mut TestComponents, _entity: Entity)
+        {
+            components.log.push("remove".to_string());
+        }
+    }
+
+    struct TestServices;
+
+    impl ServiceManager for TestServices {}
+
+    #[derive(Default)]
+    struct TestSystems
+    {
+        activated_calls: usize,
+        deactivated_calls: usize,
+        refresh_calls: usize,
+    }
+
+    impl SystemManager for TestSystems
+    {
+        type Components = TestComponents;
+        type Services = TestServices;
+
+        fn __activated(&mut self, _entity: EntityData<TestComponents>, c: &mut TestComponents, _m: &mut TestServices)
+        {
+            self.activated_calls += 1;
+            c.log.push("activated".to_string());
+        }
+
+        fn __deactivated(&mut self, _entity: EntityData<TestComponents>, c: &mut TestComponents, _m: &mut TestServices)
+        {
+            self.deactivated_calls += 1;
+            c.log.push("deactivated".to_string());
+        }
+
+        fn __refresh(&mut self, _entity: EntityData<TestComponents>, c: &mut TestComponents, _m: &mut TestServices)
+        {
+            self.refresh_calls += 1;
+            c.log.push("refreshed".to_string());
+        }
+    }
+
+    struct TestBuilder;
+
+    impl EntityBuilder<TestComponents> for TestBuilder
+    {
+        fn build(self, _entity: BuildData<TestComponents>, _c: &mut TestComponents) {}
+    }
+
+    #[test]
+    fn components_changed_after_remove_is_skipped_not_panicked()
+    {
+        let mut manager = EntityManager::<TestComponents>::new();
+        let mut components = TestComponents::default();
+        let mut services = TestServices;
+        let mut systems = TestSystems::default();
+
+        let entity = manager.create_entity(TestBuilder, &mut components);
+        manager.flush_queue(&mut components, &mut services, &mut systems);
+
+        manager.remove_entity(entity);
+        manager.notify_changed(entity);
+        manager.flush_queue(&mut components, &mut services, &mut systems);
+
+        assert!(!manager.is_valid(&entity));
+    }
+
+    #[test]
+    fn duplicate_remove_entity_is_skipped_not_panicked()
+    {
+        let mut manager = EntityManager::<TestComponents>::new();
+        let mut components = TestComponents::default();
+        let mut services = TestServices;
+        let mut systems = TestSystems::default();
+
+        let entity = manager.create_entity(TestBuilder, &mut components);
+        manager.flush_queue(&mut components, &mut services, &mut systems);
+
+        manager.remove_entity(entity);
+        manager.remove_entity(entity);
+        manager.flush_queue(&mut components, &mut services, &mut systems);
+
+        assert!(!manager.is_valid(&entity));
+        assert_eq!(systems.deactivated_calls, 1);
+    }
+
+    #[test]
+    fn command_buffer_applies_in_submission_order()
+    {
+        let mut manager = EntityManager::<TestComponents>::new();
+        let mut components = TestComponents::default();
+        let mut services = TestServices;
+        let mut systems = TestSystems::default();
+
+        let entity = manager.create_entity(TestBuilder, &mut components);
+
+        let mut commands = CommandBuffer::new();
+        commands.add_component(entity, Marker);
+        commands.remove_component::<Marker>(entity);
+        manager.append_commands(&mut commands);
+
+        manager.flush_queue(&mut components, &mut services, &mut systems);
+
+        // Each component mutation refreshes membership right after it's applied, so "insert"
+        // and "remove" are each immediately followed by a "refreshed" rather than leaving
+        // membership recomputation for the caller to trigger separately.
+        assert_eq!(components.log, vec!["activated", "insert", "refreshed", "remove", "refreshed"]);
+    }
+
+    #[test]
+    fn snapshot_rollback_round_trip()
+    {
+        let mut manager = EntityManager::<TestComponents>::new();
+        let mut components = TestComponents::default();
+        let mut services = TestServices;
+        let mut systems = TestSystems::default();
+
+        // create_entity queues a BuildEntity that hasn't been flushed yet; the snapshot is taken
+        // with it still pending, so rollback must preserve it rather than discard it.
+        let kept = manager.create_entity(TestBuilder, &mut components);
+        let snapshot = manager.snapshot();
+
+        let discarded = manager.create_entity(TestBuilder, &mut components);
+        manager.notify_changed(discarded);
+
+        manager.rollback(snapshot);
+
+        assert!(manager.is_valid(&kept));
+        assert!(!manager.is_valid(&discarded));
+        assert_eq!(manager.count(), 1);
+
+        // The pre-snapshot BuildEntity(kept) survived the rollback and still flushes cleanly;
+        // the post-snapshot events referencing `discarded` (now an invalid slot) did not.
+        assert_eq!(manager.event_queue.queue.len(), 1);
+        manager.flush_queue(&mut components, &mut services, &mut systems);
+        assert_eq!(systems.activated_calls, 1);
+    }
+
+    #[test]
+    fn rollback_after_intervening_flush_clears_unrelated_queue()
+    {
+        let mut manager = EntityManager::<TestComponents>::new();
+        let mut components = TestComponents::default();
+        let mut services = TestServices;
+        let mut systems = TestSystems::default();
+
+        // One event is pending at snapshot time, so the recorded queue length is 1.
+        manager.notify_changed(Entity::nil());
+        let snapshot = manager.snapshot();
+
+        // Unlike snapshot_rollback_round_trip, a flush happens between snapshot and rollback, so
+        // the recorded length no longer means anything: truncating to it would keep the
+        // RemoveEntity queued for an entity the rollback is about to invalidate.
+        let entity = manager.create_entity(TestBuilder, &mut components);
+        manager.flush_queue(&mut components, &mut services, &mut systems);
+        manager.remove_entity(entity);
+
+        manager.rollback(snapshot);
+
+        assert_eq!(manager.count(), 0);
+        assert!(manager.event_queue.queue.is_empty());
+    }
+
+    #[test]
+    fn recycled_index_bumps_generation()
+    {
+        let mut pool = IndexPool::new();
+
+        let (index, generation) = pool.get_index();
+        assert!(pool.is_valid(index, generation));
+
+        pool.return_id(index);
+        assert!(!pool.is_valid(index, generation));
+
+        let (recycled_index, recycled_generation) = pool.get_index();
+        assert_eq!(recycled_index, index);
+        assert!(recycled_generation > generation);
+        assert!(pool.is_valid(recycled_index, recycled_generation));
+        assert!(!pool.is_valid(index, generation));
     }
 }